@@ -1,13 +1,16 @@
 //! Utility methods
 
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
 use std::ops::AddAssign;
 use std::str::FromStr;
+use std::sync::Mutex;
 
 use bytesize::ByteSize;
 use clap::Parser;
+use rayon::prelude::*;
+use serde::Serialize;
 
 #[derive(Parser, Debug)]
 #[clap(version = "1.0", author = "Havvoric <havvoric@gmail.com>")]
@@ -22,6 +25,73 @@ pub struct Opts {
     reverse: bool,
     #[clap(short = 'z', long)]
     show_summary: bool,
+
+    /// Cap the number of worker threads used to walk trees in parallel
+    /// (defaults to the number of available cores)
+    #[clap(short, long)]
+    jobs: Option<usize>,
+
+    /// Count every hard link to a file separately, instead of
+    /// deduplicating by (device, inode)
+    #[clap(long)]
+    count_links: bool,
+
+    /// Use on-disk allocated size (`blocks() * 512`, as `du` reports)
+    /// instead of the apparent (logical) file length
+    #[clap(short, long = "disk-usage")]
+    disk_usage: bool,
+
+    /// Render a hierarchical tree view, with each node's share of the
+    /// grand total shown as a bar, instead of the flat table
+    #[clap(long)]
+    tree: bool,
+    /// Maximum depth to descend to in tree mode (unlimited if unset)
+    #[clap(long)]
+    depth: Option<usize>,
+    /// Collapse tree nodes smaller than this size into a single
+    /// aggregated "<N files, SIZE>" line (e.g. `--min-size 10KB`)
+    #[clap(long = "min-size")]
+    min_size: Option<ByteSize>,
+
+    /// Skip paths matching this glob (repeatable); checked against the
+    /// file/directory name, and pruned during the walk so excluded
+    /// directories are never descended into
+    #[clap(short = 'x', long = "exclude")]
+    excludes: Vec<String>,
+
+    /// Retain per-folder file sizes and report these percentiles, e.g.
+    /// `--percentiles p50,p90,p99`; `--sort p50`/`p90`/`p99` collects its
+    /// own percentile whether or not it is listed here
+    #[clap(long, value_delimiter = ',')]
+    percentiles: Vec<String>,
+
+    /// Output format: `table` (default), `json`, or `csv`
+    #[clap(short, long = "output", value_enum)]
+    output: Option<OutputFormat>,
+}
+
+/// Structured output formats for `Processor::process`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The default space-separated columns plus an ASCII summary banner
+    Table,
+    /// A JSON array of row objects
+    Json,
+    /// Comma-separated rows, one per line
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err("no match"),
+        }
+    }
 }
 
 /// Defined orderings for results
@@ -37,9 +107,18 @@ pub enum SortMode {
     Max,
     /// Sort by maximum size of internal files
     Min,
+    /// Sort by median (p50) file size; p50 is collected even if `--percentiles` omits it
+    P50,
+    /// Sort by 90th-percentile file size; p90 is collected even if `--percentiles` omits it
+    P90,
+    /// Sort by 99th-percentile file size; p99 is collected even if `--percentiles` omits it
+    P99,
 }
 
-/// Details of internal files within a folder
+/// Details of internal files within a folder.
+///
+/// Sizes reflect apparent (logical) file length by default, or on-disk
+/// allocated size when `Opts::disk_usage` is set; see `Processor::size_of`.
 #[derive(Debug)]
 pub struct ChildSizeEntry {
     /// Number of internal files
@@ -52,12 +131,69 @@ pub struct ChildSizeEntry {
     max: ByteSize,
     /// Minimum size of internal files
     min: ByteSize,
+    /// Raw file sizes, retained only when percentiles are computed
+    /// (`--percentiles` or a percentile `--sort`); sorted in place by
+    /// `compute_percentiles`
+    samples: Vec<u64>,
+    /// Percentile (e.g. 50, 90, 99) to size, populated by `compute_percentiles`
+    percentile_values: HashMap<u8, ByteSize>,
 }
 
 impl ChildSizeEntry {
     fn update_average(&mut self) {
         self.average = ByteSize::b((self.total.as_u64() as f64 / self.count as f64) as u64);
     }
+
+    /// Sort the retained samples once and compute the requested percentiles,
+    /// indexing at `ceil(p / 100 * n) - 1` as is conventional for the
+    /// nearest-rank method.
+    fn compute_percentiles(&mut self, percentiles: &[u8]) {
+        if self.samples.is_empty() || percentiles.is_empty() {
+            return;
+        }
+        self.samples.sort_unstable();
+        let n = self.samples.len();
+        for &p in percentiles {
+            let rank = ((p as f64 / 100.0 * n as f64).ceil() as usize)
+                .saturating_sub(1)
+                .min(n - 1);
+            self.percentile_values
+                .insert(p, ByteSize::b(self.samples[rank]));
+        }
+    }
+
+    fn percentile(&self, p: u8) -> ByteSize {
+        self.percentile_values.get(&p).copied().unwrap_or_default()
+    }
+
+    /// A structured row for JSON/CSV output: raw byte counts rather than
+    /// `ByteSize`'s formatted units, with `min` a first-class field.
+    ///
+    /// `min` is left at its `ByteSize::pib(1)` sentinel when `count` is zero
+    /// (nothing was ever smaller than it); clamp that case to `0` so the
+    /// sentinel never leaks into structured output.
+    fn as_row<'a>(&self, path: &'a str) -> Row<'a> {
+        let min_bytes = if self.count == 0 { 0 } else { self.min.as_u64() };
+        Row {
+            path,
+            count: self.count,
+            total_bytes: self.total.as_u64(),
+            average_bytes: self.average.as_u64(),
+            max_bytes: self.max.as_u64(),
+            min_bytes,
+        }
+    }
+}
+
+/// A single structured-output record (see `OutputFormat::Json`/`Csv`)
+#[derive(Debug, Serialize)]
+struct Row<'a> {
+    path: &'a str,
+    count: u64,
+    total_bytes: u64,
+    average_bytes: u64,
+    max_bytes: u64,
+    min_bytes: u64,
 }
 
 impl Default for ChildSizeEntry {
@@ -68,6 +204,8 @@ impl Default for ChildSizeEntry {
             average: Default::default(),
             max: Default::default(),
             min: ByteSize::pib(1),
+            samples: Vec::new(),
+            percentile_values: HashMap::new(),
         }
     }
 }
@@ -78,7 +216,13 @@ impl Display for ChildSizeEntry {
             f,
             "{} {} {} {}",
             self.count, self.total, self.average, self.max
-        )
+        )?;
+        let mut percentiles: Vec<&u8> = self.percentile_values.keys().collect();
+        percentiles.sort_unstable();
+        for p in percentiles {
+            write!(f, " p{}={}", p, self.percentile_values[p])?;
+        }
+        Ok(())
     }
 }
 
@@ -95,6 +239,22 @@ impl AddAssign<ByteSize> for ChildSizeEntry {
     }
 }
 
+impl AddAssign<&ChildSizeEntry> for ChildSizeEntry {
+    /// Merge a partial result (e.g. from a worker thread) into this entry.
+    /// `average` is left stale; call `update_average` once merging is done.
+    fn add_assign(&mut self, other: &ChildSizeEntry) {
+        self.count += other.count;
+        self.total += other.total;
+        if self.max < other.max {
+            self.max = other.max;
+        }
+        if self.min > other.min {
+            self.min = other.min;
+        }
+        self.samples.extend_from_slice(&other.samples);
+    }
+}
+
 impl FromStr for SortMode {
     type Err = &'static str;
 
@@ -105,6 +265,9 @@ impl FromStr for SortMode {
             "average" => Ok(Self::Average),
             "max" => Ok(Self::Max),
             "min" => Ok(Self::Min),
+            "p50" => Ok(Self::P50),
+            "p90" => Ok(Self::P90),
+            "p99" => Ok(Self::P99),
             _ => Err("no match"),
         }
     }
@@ -113,6 +276,18 @@ impl FromStr for SortMode {
 type OrderProc = fn(&(&String, &ChildSizeEntry), &(&String, &ChildSizeEntry)) -> Ordering;
 
 impl SortMode {
+    /// The percentile this sort mode reads from `ChildSizeEntry::percentile`,
+    /// if any; used to make sure that percentile is always collected even if
+    /// `--percentiles` didn't request it.
+    fn percentile(&self) -> Option<u8> {
+        match self {
+            SortMode::P50 => Some(50),
+            SortMode::P90 => Some(90),
+            SortMode::P99 => Some(99),
+            _ => None,
+        }
+    }
+
     fn ordering(&self) -> OrderProc {
         match self {
             SortMode::Count => |a, b| a.1.count.partial_cmp(&b.1.count).unwrap(),
@@ -120,42 +295,171 @@ impl SortMode {
             SortMode::Total => |a, b| a.1.total.partial_cmp(&b.1.total).unwrap(),
             SortMode::Max => |a, b| a.1.max.partial_cmp(&b.1.max).unwrap(),
             SortMode::Min => |a, b| a.1.min.partial_cmp(&b.1.min).unwrap(),
+            SortMode::P50 => |a, b| a.1.percentile(50).partial_cmp(&b.1.percentile(50)).unwrap(),
+            SortMode::P90 => |a, b| a.1.percentile(90).partial_cmp(&b.1.percentile(90)).unwrap(),
+            SortMode::P99 => |a, b| a.1.percentile(99).partial_cmp(&b.1.percentile(99)).unwrap(),
+        }
+    }
+}
+
+/// A node in the `--tree` hierarchy, for a folder or a file: the sizes
+/// recorded at exactly this path (`own`, non-empty for a file), its
+/// entries, both files and subfolders (`children`), and the rolled-up size
+/// of the whole subtree (`total`, populated by `finalize`). Children are
+/// keyed by path component and printed in that (lexical) order.
+#[derive(Debug, Default)]
+struct Node {
+    own: ChildSizeEntry,
+    total: ByteSize,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    /// Insert a file's size at the path described by `components`,
+    /// creating intermediate nodes as needed.
+    fn insert(&mut self, components: &[String], size: ByteSize) {
+        match components.split_first() {
+            None => self.own += size,
+            Some((head, rest)) => self
+                .children
+                .entry(head.clone())
+                .or_default()
+                .insert(rest, size),
+        }
+    }
+
+    /// Merge another (partial) tree into this one.
+    fn merge(&mut self, other: Node) {
+        self.own += &other.own;
+        for (name, child) in other.children {
+            self.children.entry(name).or_default().merge(child);
+        }
+    }
+
+    /// Post-order pass: roll each node's own size plus its children's
+    /// totals up into `total`, returning that total.
+    fn finalize(&mut self) -> ByteSize {
+        let mut total = self.own.total;
+        for child in self.children.values_mut() {
+            total += child.finalize();
         }
+        self.total = total;
+        total
     }
 }
 
+/// Per-walk settings threaded through `filefold`, bundled to keep its
+/// signature manageable as the tool has grown more flags.
+struct FoldContext<'a> {
+    base: &'a str,
+    globset: &'a globset::GlobSet,
+    exclude_globset: &'a globset::GlobSet,
+    include_all: bool,
+    seen_inodes: &'a Mutex<HashSet<(u64, u64)>>,
+    count_links: bool,
+    disk_usage: bool,
+    track_percentiles: bool,
+    build_tree: bool,
+}
+
 #[derive(Debug)]
 pub struct Processor {
     summary: ChildSizeEntry,
     entries: HashMap<String, ChildSizeEntry>,
     opts: Opts,
+    /// (device, inode) pairs already counted, to avoid double-counting hard links
+    seen_inodes: Mutex<HashSet<(u64, u64)>>,
+    /// Root of the `--tree` hierarchy; its direct children are the walked paths
+    tree: Node,
 }
 
 impl Processor {
     pub fn new(opts: Opts) -> Self {
         Self {
             opts,
-            summary: ChildSizeEntry {
-                min: ByteSize::pib(1),
-                count: 0,
-                total: bytesize::ByteSize(0),
-                average: bytesize::ByteSize(0),
-                max: bytesize::ByteSize(0),
-            },
+            summary: ChildSizeEntry::default(),
             entries: HashMap::new(),
+            seen_inodes: Mutex::new(HashSet::new()),
+            tree: Node::default(),
         }
     }
 
+    /// Parse the `--percentiles` strings (e.g. `"p50"`) into bare percentile
+    /// numbers, silently dropping anything unparseable, plus whichever
+    /// percentile `--sort` needs so `--sort p50` is never silently starved
+    /// of the samples it sorts by.
+    fn percentiles(&self) -> Vec<u8> {
+        let mut percentiles: Vec<u8> = self
+            .opts
+            .percentiles
+            .iter()
+            .filter_map(|p| p.trim_start_matches(['p', 'P']).parse().ok())
+            .collect();
+        if let Some(p) = self.opts.sort.percentile() {
+            if !percentiles.contains(&p) {
+                percentiles.push(p);
+            }
+        }
+        percentiles
+    }
+
     /// Walk a path, recording details of all immediate children
-    fn walktree(&mut self, path: &str, globset: &globset::GlobSet) {
-        for entry in walkdir::WalkDir::new(path)
-            .same_file_system(true)
-            .into_iter()
-            .flatten()
-        {
-            self.filefold(entry, path, globset);
+    ///
+    /// The top-level children of `ctx.base` are split into independent work
+    /// units and traversed in parallel (via rayon), with each worker
+    /// accumulating into its own local map, summary entry and (if enabled)
+    /// tree node. The caller merges the returned partial results into
+    /// `self` once every worker has finished; this takes no `&self` so it
+    /// can run inside a scope where `self.seen_inodes` is already borrowed.
+    fn walktree(ctx: &FoldContext) -> (HashMap<String, ChildSizeEntry>, ChildSizeEntry, Node) {
+        // `read_dir` fails for a non-directory root (e.g. `ctx.base` is
+        // itself a regular file); walk `ctx.base` directly in that case so
+        // a single-file argument still contributes, same as the rest of the
+        // walk treats any other child path.
+        let children: Vec<std::path::PathBuf> = match std::fs::read_dir(ctx.base) {
+            Ok(read_dir) => read_dir.flatten().map(|entry| entry.path()).collect(),
+            Err(_) => vec![std::path::PathBuf::from(ctx.base)],
+        };
+
+        let partials: Vec<(HashMap<String, ChildSizeEntry>, ChildSizeEntry, Node)> = children
+            .par_iter()
+            .map(|child| {
+                let mut entries: HashMap<String, ChildSizeEntry> = HashMap::new();
+                let mut summary = ChildSizeEntry::default();
+                let mut tree = Node::default();
+                for entry in walkdir::WalkDir::new(child)
+                    .same_file_system(true)
+                    .into_iter()
+                    .filter_entry(|e| !ctx.exclude_globset.is_match(e.file_name()))
+                    .flatten()
+                {
+                    let size = Self::filefold(&mut entries, &mut summary, &entry, ctx);
+                    if ctx.build_tree {
+                        if let (Some(size), Ok(rel)) = (size, entry.path().strip_prefix(ctx.base))
+                        {
+                            let components: Vec<String> = rel
+                                .components()
+                                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                                .collect();
+                            tree.insert(&components, size);
+                        }
+                    }
+                }
+                (entries, summary, tree)
+            })
+            .collect();
+
+        let mut entries: HashMap<String, ChildSizeEntry> = HashMap::new();
+        let mut summary = ChildSizeEntry::default();
+        let mut root = Node::default();
+        for (local_entries, local_summary, local_tree) in partials {
+            for (key, entry) in local_entries {
+                *entries.entry(key).or_default() += &entry;
+            }
+            summary += &local_summary;
+            root.merge(local_tree);
         }
-        //.fold(self, |acc, e| self.filefold(acc, e, path, globset));
+        (entries, summary, root)
     }
 
     /// Walk all paths, recording details of encountered files
@@ -166,46 +470,288 @@ impl Processor {
         }
         let globset = builder.build().unwrap();
 
+        let mut exclude_builder = globset::GlobSetBuilder::new();
+        for glob in &self.opts.excludes {
+            exclude_builder.add(globset::Glob::new(glob).unwrap());
+        }
+        let exclude_globset = exclude_builder.build().unwrap();
+
+        let pool = match self.opts.jobs {
+            Some(jobs) => rayon::ThreadPoolBuilder::new().num_threads(jobs).build(),
+            None => rayon::ThreadPoolBuilder::new().build(),
+        }
+        .expect("failed to build rayon thread pool");
+
+        // An empty `globset` (no `--pattern` flags given) matches nothing on
+        // its own, so without `include_all` the no-patterns default would
+        // silently record zero files instead of "no filter, include
+        // everything". This is an intentional behavior change from the
+        // pre-exclude-glob baseline, where the same empty globset meant
+        // "match nothing" with no override.
+        let include_all = self.opts.patterns.is_empty();
+        let count_links = self.opts.count_links;
+        let disk_usage = self.opts.disk_usage;
+        let track_percentiles = !self.percentiles().is_empty();
+        let build_tree = self.opts.tree;
+        let seen_inodes = &self.seen_inodes;
+
         let paths = self.opts.paths.clone();
-        for path in paths {
-            self.walktree(&path, &globset);
+        // Collect every path's partial results before touching `self`: the
+        // `&self.seen_inodes` borrow captured here must not still be live
+        // when `self` is mutated below.
+        let results: Vec<(HashMap<String, ChildSizeEntry>, ChildSizeEntry, Node)> =
+            pool.install(|| {
+                paths
+                    .iter()
+                    .map(|path| {
+                        let ctx = FoldContext {
+                            base: path,
+                            globset: &globset,
+                            exclude_globset: &exclude_globset,
+                            include_all,
+                            seen_inodes,
+                            count_links,
+                            disk_usage,
+                            track_percentiles,
+                            build_tree,
+                        };
+                        Self::walktree(&ctx)
+                    })
+                    .collect()
+            });
+
+        for (path, (path_entries, path_summary, path_tree)) in paths.iter().zip(results) {
+            for (key, entry) in path_entries {
+                *self.entries.entry(key).or_default() += &entry;
+            }
+            self.summary += &path_summary;
+            if build_tree {
+                self.tree
+                    .children
+                    .entry(path.clone())
+                    .or_default()
+                    .merge(path_tree);
+            }
         }
     }
 
     /// Produce table to stdout, based on supplied sorting and direction
     pub fn process(&mut self) {
+        if self.opts.tree {
+            self.process_tree();
+            return;
+        }
+
+        let percentiles = self.percentiles();
         let mut entries: Vec<(&String, &ChildSizeEntry)> = Vec::new();
         for (file, entry) in self.entries.iter_mut() {
             entry.update_average();
+            entry.compute_percentiles(&percentiles);
             entries.push((file, entry));
         }
         entries.sort_unstable_by(self.opts.sort.ordering());
         if self.opts.reverse {
             entries.reverse();
         }
-        for entry in entries {
-            println!("{} {}", entry.1, entry.0);
+        self.summary.update_average();
+        self.summary.compute_percentiles(&percentiles);
+
+        match self.opts.output.unwrap_or(OutputFormat::Table) {
+            OutputFormat::Table => Self::print_table(&entries, &self.summary, self.opts.show_summary),
+            OutputFormat::Json => Self::print_json(&entries, &self.summary, self.opts.show_summary),
+            OutputFormat::Csv => Self::print_csv(&entries, &self.summary, self.opts.show_summary),
         }
-        if self.opts.show_summary {
-            self.summary.update_average();
-            let summary = format!("{} SUMMARY", self.summary);
+    }
+
+    /// Default output: one space-separated row per entry, plus an ASCII
+    /// summary banner when `show_summary` is set
+    fn print_table(entries: &[(&String, &ChildSizeEntry)], summary: &ChildSizeEntry, show_summary: bool) {
+        for (file, entry) in entries {
+            println!("{entry} {file}");
+        }
+        if show_summary {
+            let summary = format!("{summary} SUMMARY");
             println!("{}", "=".repeat(summary.len()));
-            println!("{}", summary);
+            println!("{summary}");
             println!("{}", "=".repeat(summary.len()));
         }
     }
 
-    fn filefold(&mut self, e: walkdir::DirEntry, base: &str, globset: &globset::GlobSet) {
-        if e.file_type().is_file() && globset.is_match(e.file_name()) {
-            let key = Self::key(e.path(), base).unwrap_or_default();
+    /// `--output json`: a JSON array of rows, with the summary (if
+    /// requested) appended as a row whose path is `"SUMMARY"`
+    fn print_json(entries: &[(&String, &ChildSizeEntry)], summary: &ChildSizeEntry, show_summary: bool) {
+        let mut rows: Vec<Row> = entries.iter().map(|(path, entry)| entry.as_row(path)).collect();
+        if show_summary {
+            rows.push(summary.as_row("SUMMARY"));
+        }
+        match serde_json::to_string_pretty(&rows) {
+            Ok(json) => println!("{json}"),
+            Err(err) => eprintln!("failed to serialize output as JSON: {err}"),
+        }
+    }
+
+    /// `--output csv`: one row per line, with the summary (if requested)
+    /// appended as a row whose path is `"SUMMARY"`
+    fn print_csv(entries: &[(&String, &ChildSizeEntry)], summary: &ChildSizeEntry, show_summary: bool) {
+        let mut writer = csv::Writer::from_writer(std::io::stdout());
+        for (path, entry) in entries {
+            if let Err(err) = writer.serialize(entry.as_row(path)) {
+                eprintln!("failed to write CSV row: {err}");
+                return;
+            }
+        }
+        if show_summary {
+            if let Err(err) = writer.serialize(summary.as_row("SUMMARY")) {
+                eprintln!("failed to write CSV row: {err}");
+                return;
+            }
+        }
+        if let Err(err) = writer.flush() {
+            eprintln!("failed to flush CSV output: {err}");
+        }
+    }
+
+    /// Render `self.tree` as an indented hierarchy with per-node usage bars
+    fn process_tree(&mut self) {
+        let grand_total = self.tree.finalize().as_u64();
+        let min_bytes = self.opts.min_size.map(|s| s.as_u64()).unwrap_or_default();
+
+        for (name, child) in &self.tree.children {
+            Self::print_node(name, child, grand_total, 0, self.opts.depth, min_bytes, "");
+        }
+    }
+
+    /// Recursively print `node` and (unless `max_depth` has been reached)
+    /// its children, collapsing any child smaller than `min_bytes` into a
+    /// trailing aggregated line.
+    fn print_node(
+        name: &str,
+        node: &Node,
+        grand_total: u64,
+        depth: usize,
+        max_depth: Option<usize>,
+        min_bytes: u64,
+        indent: &str,
+    ) {
+        let pct = if grand_total == 0 {
+            0.0
+        } else {
+            node.total.as_u64() as f64 / grand_total as f64 * 100.0
+        };
+        println!("{}{} {} {}", indent, Self::bar(pct), node.total, name);
+
+        if max_depth.is_some_and(|max_depth| depth >= max_depth) {
+            return;
+        }
+
+        let child_indent = format!("{indent}  ");
+        let mut collapsed_count = 0u64;
+        let mut collapsed_total = 0u64;
+        for (child_name, child) in &node.children {
+            if child.total.as_u64() < min_bytes {
+                collapsed_count += 1;
+                collapsed_total += child.total.as_u64();
+                continue;
+            }
+            Self::print_node(
+                child_name,
+                child,
+                grand_total,
+                depth + 1,
+                max_depth,
+                min_bytes,
+                &child_indent,
+            );
+        }
+        if collapsed_count > 0 {
+            println!(
+                "{}<{} files, {}>",
+                child_indent,
+                collapsed_count,
+                ByteSize::b(collapsed_total)
+            );
+        }
+    }
+
+    /// Render a fixed-width usage bar, e.g. `[████░░░░░░]  38%`
+    fn bar(pct: f64) -> String {
+        const WIDTH: usize = 10;
+        let filled = ((pct / 100.0) * WIDTH as f64).round().clamp(0.0, WIDTH as f64) as usize;
+        format!(
+            "[{}{}] {:>3.0}%",
+            "█".repeat(filled),
+            "░".repeat(WIDTH - filled),
+            pct
+        )
+    }
+
+    /// Fold a single walked entry into `entries`/`summary`, returning the
+    /// size it was counted at (or `None` if it was skipped: not a file,
+    /// excluded, not matching any include pattern, unreadable metadata, or
+    /// an already-seen hard link).
+    fn filefold(
+        entries: &mut HashMap<String, ChildSizeEntry>,
+        summary: &mut ChildSizeEntry,
+        e: &walkdir::DirEntry,
+        ctx: &FoldContext,
+    ) -> Option<ByteSize> {
+        let included = ctx.include_all || ctx.globset.is_match(e.file_name());
+        let excluded = ctx.exclude_globset.is_match(e.file_name());
+        if e.file_type().is_file() && included && !excluded {
+            let key = Self::key(e.path(), ctx.base).unwrap_or_default();
             if let Ok(metadata) = e.metadata() {
-                // println!("key={}, path={:?}, base={}, file_name={:?}", key, e.path(), base, e.file_name());
-                let entry = self.entries.entry(key).or_default();
-                let size = ByteSize::b(metadata.len());
+                // println!("key={}, path={:?}, base={}, file_name={:?}", key, e.path(), ctx.base, e.file_name());
+                if !ctx.count_links && !Self::first_link(&metadata, ctx.seen_inodes) {
+                    return None;
+                }
+                let entry = entries.entry(key).or_default();
+                let size = Self::size_of(&metadata, ctx.disk_usage);
                 *entry += size;
-                self.summary += size;
+                *summary += size;
+                if ctx.track_percentiles {
+                    entry.samples.push(size.as_u64());
+                    summary.samples.push(size.as_u64());
+                }
+                return Some(size);
             }
         }
+        None
+    }
+
+    /// Size to attribute to a file: apparent (logical) length by default,
+    /// or on-disk allocated size (`blocks() * 512`, matching `du`) when
+    /// `disk_usage` is set. Platforms without block-count metadata always
+    /// report the apparent length.
+    #[cfg(unix)]
+    fn size_of(metadata: &std::fs::Metadata, disk_usage: bool) -> ByteSize {
+        use std::os::unix::fs::MetadataExt;
+        if disk_usage {
+            ByteSize::b(metadata.blocks() * 512)
+        } else {
+            ByteSize::b(metadata.len())
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn size_of(metadata: &std::fs::Metadata, _disk_usage: bool) -> ByteSize {
+        ByteSize::b(metadata.len())
+    }
+
+    /// Record a file's (device, inode) pair, returning `true` the first
+    /// time it is seen. Platforms without inode metadata always report a
+    /// fresh link, i.e. every file is counted.
+    #[cfg(unix)]
+    fn first_link(metadata: &std::fs::Metadata, seen_inodes: &Mutex<HashSet<(u64, u64)>>) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        seen_inodes
+            .lock()
+            .unwrap()
+            .insert((metadata.dev(), metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn first_link(_metadata: &std::fs::Metadata, _seen_inodes: &Mutex<HashSet<(u64, u64)>>) -> bool {
+        true
     }
 
     fn key(path: &std::path::Path, base: &str) -> Option<String> {
@@ -267,4 +813,82 @@ mod tests {
         let r = Processor::key(path, "/test2/");
         assert!(r.is_none());
     }
+
+    #[test]
+    fn test_merge_entries() {
+        let mut a = ChildSizeEntry::default();
+        a += ByteSize::b(10);
+        a += ByteSize::b(30);
+
+        let mut b = ChildSizeEntry::default();
+        b += ByteSize::b(5);
+
+        a += &b;
+
+        assert_eq!(a.count, 3);
+        assert_eq!(a.total, ByteSize::b(45));
+        assert_eq!(a.max, ByteSize::b(30));
+        assert_eq!(a.min, ByteSize::b(5));
+    }
+
+    #[test]
+    fn test_tree_insert_and_finalize() {
+        let mut root = Node::default();
+        root.insert(&["a".to_string(), "1.txt".to_string()], ByteSize::b(10));
+        root.insert(&["a".to_string(), "2.txt".to_string()], ByteSize::b(20));
+        root.insert(&["b.txt".to_string()], ByteSize::b(5));
+
+        let total = root.finalize();
+
+        assert_eq!(total, ByteSize::b(35));
+        assert_eq!(root.children["a"].total, ByteSize::b(30));
+        assert_eq!(root.children["b.txt"].total, ByteSize::b(5));
+    }
+
+    #[test]
+    fn test_tree_merge() {
+        let mut a = Node::default();
+        a.insert(&["x".to_string()], ByteSize::b(10));
+
+        let mut b = Node::default();
+        b.insert(&["x".to_string()], ByteSize::b(5));
+        b.insert(&["y".to_string()], ByteSize::b(1));
+
+        a.merge(b);
+        let total = a.finalize();
+
+        assert_eq!(total, ByteSize::b(16));
+        assert_eq!(a.children["x"].total, ByteSize::b(15));
+    }
+
+    #[test]
+    fn test_no_patterns_means_include_all() {
+        // An empty GlobSet (no `--pattern` flags) matches nothing on its
+        // own; `include_all` is what turns "no patterns given" into
+        // "include every file" rather than "include none", mirroring the
+        // `included` check in `filefold`.
+        let globset = globset::GlobSetBuilder::new().build().unwrap();
+        assert!(!globset.is_match("anything.txt"));
+
+        let include_all = true;
+        assert!(include_all || globset.is_match("anything.txt"));
+
+        let include_all = false;
+        assert!(!(include_all || globset.is_match("anything.txt")));
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut entry = ChildSizeEntry::default();
+        for size in [10, 20, 30, 40, 50] {
+            entry += ByteSize::b(size);
+            entry.samples.push(size);
+        }
+
+        entry.compute_percentiles(&[50, 90]);
+
+        assert_eq!(entry.percentile(50), ByteSize::b(30));
+        assert_eq!(entry.percentile(90), ByteSize::b(50));
+        assert_eq!(entry.percentile(99), ByteSize::b(0));
+    }
 }